@@ -16,6 +16,14 @@
 
 use super::*;
 
+use std::cell::UnsafeCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once, RwLock};
+
 /// A compressed span.
 /// Contains either fields of `SpanData` inline if they are small, or index into span interner.
 /// The primary goal of `Span` is to be as small as possible and fit into other structures
@@ -84,6 +92,29 @@ const INLINE2_OFFSETS: [u32; 3] = [14, 13, 2];
 const INTERNED_INDEX_SIZE: u32 = 30;
 const INTERNED_INDEX_OFFSET: u32 = 2;
 
+// The 30-bit interned index is split by *value*, not by a flag bit, into two
+// regions:
+//   - `[0, DIRECT_CAP)` indexes directly into `GLOBAL_SPAN_INTERNER`'s
+//     sharded storage. Entries here are permanent for the life of the
+//     process (until `reset_span_interner`); this is the fast, common path,
+//     and its budget is sized generously for ordinary compilations.
+//   - `[DIRECT_CAP, 1 << INTERNED_INDEX_SIZE)` indexes into
+//     `GLOBAL_EXTENDED_SPAN_INTERNER`'s side table, which gets everything
+//     left over -- `EXTENDED_CAP` is sixty-three times `DIRECT_CAP`.
+// Splitting by value instead of reserving a whole bit as a marker is what
+// lets the extended region end up genuinely larger than the direct one: a
+// single marker bit would always halve the 30-bit space evenly between the
+// two, capping their *sum* at exactly what a single unsplit 30-bit index
+// could already address.
+//
+// `EXTENDED_CAP` is still finite -- a fixed-width index can only ever
+// address `1 << INTERNED_INDEX_SIZE` distinct values, no matter how the bits
+// are partitioned -- so `GLOBAL_EXTENDED_SPAN_INTERNER` recycles its oldest
+// slots rather than refusing spans once that budget is also exhausted. See
+// `ExtendedSpanInterner` for what that means for span lifetimes.
+const DIRECT_CAP: u32 = 1 << 24;
+const EXTENDED_CAP: u32 = (1 << INTERNED_INDEX_SIZE) - DIRECT_CAP;
+
 fn encode(sd: &SpanData) -> Span {
     let (base, len, ctxt) = (sd.lo.0, sd.hi.0 - sd.lo.0, sd.ctxt.0);
 
@@ -99,17 +130,29 @@ fn encode(sd: &SpanData) -> Span {
     };
 
     let val = if fits(INLINE0_SIZES) {
+        ENCODING_STATS.inline0.fetch_add(1, Ordering::Relaxed);
         compose(INLINE0_OFFSETS, TAG_INLINE0)
     } else if fits(INLINE1_SIZES) {
+        ENCODING_STATS.inline1.fetch_add(1, Ordering::Relaxed);
         compose(INLINE1_OFFSETS, TAG_INLINE1)
     } else if fits(INLINE2_SIZES) {
+        ENCODING_STATS.inline2.fetch_add(1, Ordering::Relaxed);
         compose(INLINE2_OFFSETS, TAG_INLINE2)
     } else {
-        let index = with_span_interner(|interner| interner.intern(sd));
-        if (index >> INTERNED_INDEX_SIZE) == 0 {
-            (index << INTERNED_INDEX_OFFSET) | TAG_INTERNED
-        } else {
-            panic!("too many spans in a crate");
+        ENCODING_STATS.interned.fetch_add(1, Ordering::Relaxed);
+        match GLOBAL_SPAN_INTERNER.try_intern(sd) {
+            Some(index) => (index << INTERNED_INDEX_OFFSET) | TAG_INTERNED,
+            None => {
+                // The direct, sharded interner's `DIRECT_CAP` budget is
+                // full. `try_intern` refused the insert (rather than
+                // growing the shard further) so we fall back to the
+                // extended interner, whose slots start right after
+                // `DIRECT_CAP` and never need to signal failure: it
+                // recycles its own oldest entries instead.
+                let slot = GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap().intern(sd);
+                let index = DIRECT_CAP + slot as u32;
+                (index << INTERNED_INDEX_OFFSET) | TAG_INTERNED
+            }
         }
     };
     Span(val)
@@ -142,40 +185,424 @@ fn decode(span: Span) -> SpanData {
         ),
         TAG_INTERNED => {
             let index = extract(INTERNED_INDEX_OFFSET, INTERNED_INDEX_SIZE);
-            return with_span_interner(|interner| *interner.get(index));
+            return if index < DIRECT_CAP {
+                GLOBAL_SPAN_INTERNER.get(index)
+            } else {
+                let slot = (index - DIRECT_CAP) as u64;
+                GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap().get(slot)
+            };
         }
         _ => unreachable!()
     };
     SpanData { lo: BytePos(base), hi: BytePos(base + len), ctxt: SyntaxContext(ctxt) }
 }
 
+// The interned-span index is split into a shard selector (the low bits) and
+// an in-shard offset (the high bits), so that `SHARD_BITS` picks one of
+// `NUM_SHARDS` independently-locked shards out of the 30-bit index budget.
+// Spreading spans across shards this way means `intern` and `get` calls from
+// different threads usually land on different locks and rarely contend.
+const SHARD_BITS: u32 = 5;
+const NUM_SHARDS: usize = 1 << SHARD_BITS;
+const SHARD_MASK: u32 = (NUM_SHARDS as u32) - 1;
+
 #[derive(Default)]
-struct SpanInterner {
+struct SpanInternerShard {
     spans: HashMap<SpanData, u32>,
     span_data: Vec<SpanData>,
 }
 
-impl SpanInterner {
-    fn intern(&mut self, span_data: &SpanData) -> u32 {
-        if let Some(index) = self.spans.get(span_data) {
-            return *index;
+impl SpanInternerShard {
+    // Looks up `span_data` without taking a write lock. Returns the full
+    // global index (shard id in the low bits, in-shard offset in the high
+    // bits) if this shard already holds it.
+    fn lookup(&self, span_data: &SpanData, shard: usize) -> Option<u32> {
+        self.spans.get(span_data).map(|&offset| (offset << SHARD_BITS) | shard as u32)
+    }
+
+    // Interns `span_data`, assuming the caller has already checked `lookup`
+    // under a read lock and found nothing. Returns `None`, without
+    // inserting, if doing so would push this shard's global index past
+    // `DIRECT_CAP` -- the caller should route the span to the extended
+    // interner instead.
+    fn try_intern(&mut self, span_data: &SpanData, shard: usize) -> Option<u32> {
+        if let Some(index) = self.lookup(span_data, shard) {
+            return Some(index);
+        }
+
+        let offset = self.span_data.len() as u32;
+        let index = (offset << SHARD_BITS) | shard as u32;
+        if index >= DIRECT_CAP {
+            return None;
         }
 
-        let index = self.spans.len() as u32;
         self.span_data.push(*span_data);
+        self.spans.insert(*span_data, offset);
+        Some(index)
+    }
+
+    fn get(&self, offset: u32) -> SpanData {
+        self.span_data[offset as usize]
+    }
+}
+
+// A process-global, sharded span interner. Unlike the old thread-local
+// interner, a `Span` encoded with `TAG_INTERNED` on one thread can be
+// decoded on any other thread, which is required once spans are copied
+// across worker threads in a parallel front-end.
+#[derive(Default)]
+struct SpanInterner {
+    shards: [RwLock<SpanInternerShard>; NUM_SHARDS],
+}
+
+impl SpanInterner {
+    // `try_intern` is idempotent: interning the same `SpanData` from any
+    // thread, at any time, always returns the same global index. The shard
+    // a span lives in is chosen by hashing its data, not by which thread
+    // interned it first, which is what makes that idempotency possible.
+    //
+    // Returns `None`, without inserting anything, once this shard's share of
+    // `DIRECT_CAP` is exhausted -- the caller must route the span elsewhere
+    // (the extended interner) rather than growing this structure without
+    // bound.
+    fn try_intern(&self, span_data: &SpanData) -> Option<u32> {
+        let shard = self.shard_index(span_data);
+
+        // Fast path: a read lock is enough if another thread already
+        // interned this exact span.
+        if let Some(index) = self.shards[shard].read().unwrap().lookup(span_data, shard) {
+            return Some(index);
+        }
+
+        self.shards[shard].write().unwrap().try_intern(span_data, shard)
+    }
+
+    fn get(&self, index: u32) -> SpanData {
+        let shard = (index & SHARD_MASK) as usize;
+        let offset = index >> SHARD_BITS;
+        self.shards[shard].read().unwrap().get(offset)
+    }
+
+    fn shard_index(&self, span_data: &SpanData) -> usize {
+        let mut hasher = DefaultHasher::new();
+        span_data.hash(&mut hasher);
+        (hasher.finish() as usize) & (NUM_SHARDS - 1)
+    }
+}
+
+// Side table for spans that overflow the sharded interner's direct budget.
+// `next_index` is a genuine `u64` identity that counts every distinct span
+// ever handed to `intern`, without wrapping: a process can keep discovering
+// new out-of-line spans indefinitely. What `encode`/`decode` actually pack
+// into a `Span`, though, is `next_index % EXTENDED_CAP` -- once more than
+// `EXTENDED_CAP` distinct spans have been interned here, `span_data` (which
+// only grows up to `EXTENDED_CAP` entries, then stops allocating and starts
+// overwriting in place) recycles its oldest slot for the new span, evicting
+// whatever used to live there from `spans` too.
+//
+// This trades a hard abort for a soft one: a `Span` from this table stays
+// correctly decodable only as long as fewer than `EXTENDED_CAP` *other*
+// extended spans have been interned since. That's unconditionally true for
+// any realistic compilation (`EXTENDED_CAP` is over a billion), so in
+// practice this table simply never runs out.
+#[derive(Default)]
+struct ExtendedSpanInterner {
+    spans: HashMap<SpanData, u64>,
+    span_data: Vec<SpanData>,
+    next_index: u64,
+}
+
+impl ExtendedSpanInterner {
+    fn intern(&mut self, span_data: &SpanData) -> u64 {
+        if let Some(&index) = self.spans.get(span_data) {
+            return index % EXTENDED_CAP as u64;
+        }
+
+        let index = self.next_index;
+        self.next_index += 1;
+        let slot = (index % EXTENDED_CAP as u64) as usize;
+
+        if slot < self.span_data.len() {
+            let evicted = self.span_data[slot];
+            self.spans.remove(&evicted);
+            self.span_data[slot] = *span_data;
+        } else {
+            self.span_data.push(*span_data);
+        }
         self.spans.insert(*span_data, index);
-        index
+
+        slot as u64
+    }
+
+    fn get(&self, index: u64) -> SpanData {
+        self.span_data[index as usize]
     }
+}
+
+// A process-global, lazily-initialized `&'static T`. A plain `static` can't
+// call a non-`const` constructor, so `T::default()` is deferred to the
+// first access and guarded by a `Once`.
+struct Lazy<T: Sync + 'static> {
+    once: Once,
+    value: UnsafeCell<Option<&'static T>>,
+}
+unsafe impl<T: Sync> Sync for Lazy<T> {}
 
-    fn get(&self, index: u32) -> &SpanData {
-        &self.span_data[index as usize]
+impl<T: Sync + 'static> Lazy<T> {
+    const fn new() -> Self {
+        Lazy { once: Once::new(), value: UnsafeCell::new(None) }
     }
 }
 
-// If an interner exists in TLS, return it. Otherwise, prepare a fresh one.
-fn with_span_interner<T, F: FnOnce(&mut SpanInterner) -> T>(f: F) -> T {
-    thread_local!(static INTERNER: RefCell<SpanInterner> = {
-        RefCell::new(SpanInterner::default())
-    });
-    INTERNER.with(|interner| f(&mut *interner.borrow_mut()))
+impl<T: Sync + Default + 'static> ops::Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.once.call_once(|| {
+            let value = Box::leak(Box::new(T::default()));
+            unsafe { *self.value.get() = Some(value); }
+        });
+        unsafe { (*self.value.get()).unwrap() }
+    }
+}
+
+static GLOBAL_SPAN_INTERNER: Lazy<SpanInterner> = Lazy::new();
+static GLOBAL_EXTENDED_SPAN_INTERNER: Lazy<Mutex<ExtendedSpanInterner>> = Lazy::new();
+
+// Cumulative counts of how `encode` has classified every `Span` it has ever
+// produced, one bucket per encoding format. These are process-wide and are
+// never reset by `reset_span_interner`, so long-lived hosts can watch e.g.
+// the `interned` share grow across many `check` runs.
+#[derive(Default)]
+struct EncodingStats {
+    inline0: AtomicU64,
+    inline1: AtomicU64,
+    inline2: AtomicU64,
+    interned: AtomicU64,
+}
+
+static ENCODING_STATS: EncodingStats = EncodingStats {
+    inline0: AtomicU64::new(0),
+    inline1: AtomicU64::new(0),
+    inline2: AtomicU64::new(0),
+    interned: AtomicU64::new(0),
+};
+
+/// A snapshot of how `Span`s have been encoded so far in this process, plus
+/// the current size of the out-of-line interner. See `span_encoding_stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpanEncodingStats {
+    /// Spans encoded inline using format 0 (24-bit base, 6-bit len, no ctxt).
+    pub inline0: u64,
+    /// Spans encoded inline using format 1 (22-bit base, 8-bit len, no ctxt).
+    pub inline1: u64,
+    /// Spans encoded inline using format 2 (18-bit base, 1-bit len, 11-bit ctxt).
+    pub inline2: u64,
+    /// Spans that didn't fit inline and spilled into the interner (direct
+    /// or extended).
+    pub interned: u64,
+    /// The number of distinct `SpanData` currently held by the interner
+    /// (direct shards plus the extended side table).
+    pub interner_len: usize,
+    /// A rough estimate, in bytes, of the interner's current heap footprint.
+    pub interner_bytes: usize,
+}
+
+/// Reports how many `Span`s have been encoded via each format since process
+/// start, and the current size of the out-of-line interner. Intended for
+/// long-lived hosts (IDEs, repeated `check` loops) that want to watch
+/// interner growth, e.g. to report "N% of spans spilled to the interner".
+pub fn span_encoding_stats() -> SpanEncodingStats {
+    let entry_bytes = mem::size_of::<SpanData>() + mem::size_of::<u32>();
+
+    let (mut interner_len, mut interner_bytes) = (0usize, 0usize);
+    for shard in &GLOBAL_SPAN_INTERNER.shards {
+        let shard = shard.read().unwrap();
+        interner_len += shard.span_data.len();
+        interner_bytes += shard.span_data.len() * entry_bytes;
+    }
+
+    let extended = GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap();
+    interner_len += extended.span_data.len();
+    interner_bytes += extended.span_data.len() * (mem::size_of::<SpanData>() + mem::size_of::<u64>());
+
+    SpanEncodingStats {
+        inline0: ENCODING_STATS.inline0.load(Ordering::Relaxed),
+        inline1: ENCODING_STATS.inline1.load(Ordering::Relaxed),
+        inline2: ENCODING_STATS.inline2.load(Ordering::Relaxed),
+        interned: ENCODING_STATS.interned.load(Ordering::Relaxed),
+        interner_len,
+        interner_bytes,
+    }
+}
+
+/// Empties the out-of-line span interner (both the direct shards and the
+/// extended side table), reclaiming its memory.
+///
+/// The caller must ensure no live `Span` still encodes `TAG_INTERNED`: any
+/// such `Span` decoded after this call either returns the wrong `SpanData` or
+/// panics on an out-of-bounds index, since its index no longer refers to the
+/// entry it was created from.
+/// This is typically safe to call between independent compilation sessions
+/// hosted in the same process, once all `Span`s from the previous session
+/// have been dropped.
+pub fn reset_span_interner() {
+    for shard in &GLOBAL_SPAN_INTERNER.shards {
+        let mut shard = shard.write().unwrap();
+        shard.spans.clear();
+        shard.span_data.clear();
+    }
+
+    let mut extended = GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap();
+    extended.spans.clear();
+    extended.span_data.clear();
+    extended.next_index = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `GLOBAL_SPAN_INTERNER` and `GLOBAL_EXTENDED_SPAN_INTERNER` are process-
+    // global, so tests that intern spans or call `reset_span_interner` need
+    // to run one at a time to avoid stomping on each other.
+    static TEST_LOCK: Lazy<Mutex<()>> = Lazy::new();
+
+    fn span_data(base: u32) -> SpanData {
+        SpanData { lo: BytePos(base), hi: BytePos(base + 1), ctxt: SyntaxContext(0) }
+    }
+
+    // The whole point of the sharded, process-global interner is that a
+    // `Span` encoded with `TAG_INTERNED` on one thread decodes correctly on
+    // another -- that's not true of a `thread_local!` interner, where a
+    // large span created on one thread either decodes to the wrong
+    // `SpanData` or panics on another. Exercise that directly.
+    #[test]
+    fn interned_span_round_trips_across_threads() {
+        let _guard = TEST_LOCK.lock().unwrap();
+
+        // Large enough, and with a non-zero `ctxt`, to rule out every
+        // inline format and force the real `encode`/`GLOBAL_SPAN_INTERNER`
+        // path.
+        let sd = SpanData { lo: BytePos(1_000_000), hi: BytePos(1_000_001), ctxt: SyntaxContext(1) };
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let span = Span::new(sd.lo, sd.hi, sd.ctxt);
+            tx.send(span).unwrap();
+        });
+        let span = rx.recv().unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(span.data(), sd);
+    }
+
+    #[test]
+    fn extended_interner_is_idempotent_and_round_trips() {
+        let mut interner = ExtendedSpanInterner::default();
+        let a = span_data(1);
+        let b = span_data(2);
+
+        let ia = interner.intern(&a);
+        let ib = interner.intern(&b);
+        assert_ne!(ia, ib);
+        assert_eq!(interner.get(ia), a);
+        assert_eq!(interner.get(ib), b);
+        assert_eq!(interner.intern(&a), ia);
+    }
+
+    // Drives `encode`'s fallback `None` arm for real: pre-fill the one
+    // direct shard that `overflow_sd` would land in, right up to the point
+    // where its own global index would reach `DIRECT_CAP`, without paying
+    // the cost of actually interning that many distinct spans (a plain
+    // `Vec::resize` is enough -- `try_intern`'s overflow check only looks at
+    // `span_data.len()`, not at what's in it). Then call `Span::new` itself
+    // and confirm it took the extended path and still round-trips.
+    #[test]
+    fn encode_falls_back_to_the_extended_interner_at_the_direct_boundary() {
+        let _guard = TEST_LOCK.lock().unwrap();
+
+        let overflow_sd = SpanData {
+            lo: BytePos(0xDEAD_0000),
+            hi: BytePos(0xDEAD_0001),
+            ctxt: SyntaxContext(1),
+        };
+        let shard_idx = GLOBAL_SPAN_INTERNER.shard_index(&overflow_sd);
+
+        // Smallest offset in this shard whose global index is >= DIRECT_CAP.
+        let overflowing_offset =
+            ((DIRECT_CAP - shard_idx as u32) + (NUM_SHARDS as u32 - 1)) >> SHARD_BITS;
+
+        let (shard_len_before, extended_len_before) = {
+            let mut shard = GLOBAL_SPAN_INTERNER.shards[shard_idx].write().unwrap();
+            shard.span_data.resize(overflowing_offset as usize, span_data(0));
+            (shard.span_data.len(), GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap().span_data.len())
+        };
+
+        let span = Span::new(overflow_sd.lo, overflow_sd.hi, overflow_sd.ctxt);
+        assert_eq!(span.data(), overflow_sd);
+
+        // The direct shard didn't grow -- the span was routed to the
+        // extended interner instead of being appended here.
+        let shard_len_after = GLOBAL_SPAN_INTERNER.shards[shard_idx].read().unwrap().span_data.len();
+        assert_eq!(shard_len_after, shard_len_before);
+        let extended_len_after = GLOBAL_EXTENDED_SPAN_INTERNER.lock().unwrap().span_data.len();
+        assert_eq!(extended_len_after, extended_len_before + 1);
+    }
+
+    // `ExtendedSpanInterner` recycles its oldest slot once more than
+    // `EXTENDED_CAP` distinct spans have been interned, rather than
+    // panicking. Exercise that on a local instance with the real `get`/
+    // `intern` logic but without actually allocating `EXTENDED_CAP` entries:
+    // manually winding `next_index` forward simulates having already filled
+    // the table.
+    #[test]
+    fn extended_interner_recycles_its_oldest_slot_instead_of_overflowing() {
+        let mut interner = ExtendedSpanInterner::default();
+        let a = span_data(1);
+        let ia = interner.intern(&a);
+        assert_eq!(interner.get(ia), a);
+
+        interner.next_index = EXTENDED_CAP as u64;
+        let b = span_data(2);
+        let ib = interner.intern(&b);
+
+        // `b` landed in the same slot `a` used to occupy, evicting it.
+        assert_eq!(ib, ia);
+        assert_eq!(interner.get(ib), b);
+        assert!(!interner.spans.contains_key(&a));
+
+        // Re-interning `a` now is a genuinely new entry, not the stale one.
+        let ia2 = interner.intern(&a);
+        assert_ne!(ia2, ia);
+        assert_eq!(interner.get(ia2), a);
+    }
+
+    // `reset_span_interner` empties `GLOBAL_SPAN_INTERNER`/
+    // `GLOBAL_EXTENDED_SPAN_INTERNER` for the whole process, not just this
+    // module -- `TEST_LOCK` only serializes it against the other tests in
+    // this file, not against tests elsewhere in the crate that may be
+    // interning large `Span`s concurrently under the default parallel test
+    // runner. Run it explicitly (`cargo test -- --ignored --test-threads=1`)
+    // rather than as part of the default run.
+    #[test]
+    #[ignore]
+    fn reset_span_interner_empties_the_interner() {
+        let _guard = TEST_LOCK.lock().unwrap();
+        Span::new(BytePos(0), BytePos(1 << 20), SyntaxContext(0));
+        assert!(span_encoding_stats().interner_len > 0);
+
+        reset_span_interner();
+        assert_eq!(span_encoding_stats().interner_len, 0);
+    }
+
+    #[test]
+    fn direct_and_extended_ranges_partition_the_full_index_space() {
+        // The two regions are adjacent and non-overlapping, and together
+        // cover every value a 30-bit index can hold.
+        assert_eq!(DIRECT_CAP as u64 + EXTENDED_CAP as u64, 1u64 << INTERNED_INDEX_SIZE);
+        // The whole point of splitting by value instead of by a marker bit:
+        // the rare overflow path gets the lion's share of the address space.
+        assert!(EXTENDED_CAP as u64 > DIRECT_CAP as u64 * 50);
+    }
 }